@@ -1,9 +1,11 @@
 use anyhow::{ensure, Context, Result};
 use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
-use ethers::{abi::Address, prelude::*};
-use graph_subscriptions::{Subscription, Subscriptions, TicketPayload, IERC20};
-use std::{io::Read as _, str::FromStr as _, sync::Arc};
+use ethers::{abi::Address, prelude::*, types::transaction::eip2718::TypedTransaction};
+use graph_subscriptions::{
+    PaymentRatePerSecond, Subscription, Subscriptions, TicketPayload, IERC20, TICKET_HEADER_PREFIX,
+};
+use std::{io::Read as _, path::PathBuf, str::FromStr as _, sync::Arc};
 use toolshed::url::Url;
 
 #[derive(Debug, Parser)]
@@ -17,6 +19,26 @@ struct Opt {
     subscriptions: Address,
     #[arg(long, help = "token contract address")]
     token: Address,
+    #[arg(
+        long,
+        help = "encrypted JSON keystore file, the password is prompted for on the terminal"
+    )]
+    keystore: Option<PathBuf>,
+    #[cfg(feature = "ledger")]
+    #[arg(
+        long,
+        conflicts_with = "keystore",
+        help = "sign with the Ledger Live account at this index"
+    )]
+    #[cfg_attr(feature = "aws", arg(conflicts_with = "aws_kms_key_id"))]
+    ledger: Option<usize>,
+    #[cfg(feature = "aws")]
+    #[arg(
+        long,
+        conflicts_with = "keystore",
+        help = "sign with this AWS KMS key, the region is read from the environment"
+    )]
+    aws_kms_key_id: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,25 +47,49 @@ struct Opt {
 enum Commands {
     /// show active subscription
     Active,
+    /// create a subscription, replacing the active subscription if one exists
     Subscribe {
         #[arg(long)]
         start: Option<DateTime<Utc>>,
         #[arg(long)]
         end: DateTime<Utc>,
+        #[arg(long, help = "payment rate, in token units per second")]
+        rate: PaymentRatePerSecond,
+    },
+    /// move the end of the active subscription, keeping its rate
+    Extend {
         #[arg(long)]
-        rate: u128,
+        end: DateTime<Utc>,
     },
+    /// remove the active subscription, returning unlocked tokens
     Unsubscribe,
     Collect,
-    AddAuthorizedSigner {
+    /// manage authorized signers
+    #[command(subcommand)]
+    Signer(SignerCommands),
+    /// sign and verify tickets
+    #[command(subcommand)]
+    Ticket(TicketCommands),
+}
+
+#[derive(Debug, Subcommand)]
+enum SignerCommands {
+    /// authorize a signer to sign tickets on behalf of the user
+    Add {
         #[arg(long, help = "authorized signer")]
         signer: Address,
     },
-    RemoveAuthorizedSigner {
+    /// revoke an authorized signer
+    Remove {
         #[arg(long, help = "authorized signer")]
         signer: Address,
     },
-    Ticket {
+}
+
+#[derive(Debug, Subcommand)]
+enum TicketCommands {
+    /// create a signed ticket
+    Sign {
         #[arg(long)]
         signer: Option<Address>,
         #[arg(long)]
@@ -56,37 +102,83 @@ enum Commands {
         allowed_deployments: Option<String>,
         #[arg(long)]
         allowed_domains: Option<String>,
-    },
-    Decode {
         #[arg(long)]
+        expires_at: Option<DateTime<Utc>>,
+        #[arg(long, help = "makes the ticket single-use")]
+        nonce: Option<u64>,
+    },
+    /// verify a ticket and show its payload
+    Verify {
+        #[arg(long, help = "ticket in the base64 or `v1.` header format")]
         ticket: String,
     },
 }
 
+/// Environment variable that may hold the hex-encoded secret key, instead of reading it from
+/// stdin.
+const SECRET_KEY_ENV: &str = "GRAPH_SUBS_SECRET_KEY";
+
+fn load_wallet(opt: &Opt) -> Result<LocalWallet> {
+    let wallet = if let Some(keystore) = &opt.keystore {
+        let password = rpassword::prompt_password("keystore password: ")
+            .context("failed to read keystore password")?;
+        Wallet::decrypt_keystore(keystore, password).context("failed to decrypt keystore")?
+    } else if let Ok(secret_key) = std::env::var(SECRET_KEY_ENV) {
+        Wallet::from_str(secret_key.trim()).with_context(|| format!("invalid {SECRET_KEY_ENV}"))?
+    } else {
+        eprintln!("reading secret key from stdin...");
+        let mut secret_key = String::new();
+        std::io::stdin().read_to_string(&mut secret_key)?;
+        Wallet::from_str(secret_key.trim())?
+    };
+    Ok(wallet.with_chain_id(opt.chain_id))
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let opt = Opt::parse();
     eprintln!("{:#?}", opt);
 
+    // Verifying a ticket needs neither a provider nor a secret key.
+    if let Commands::Ticket(TicketCommands::Verify { ticket }) = &opt.command {
+        return verify_ticket(ticket);
+    }
+
     let provider = Arc::new(Provider::<Http>::try_from(opt.provider.0.as_str())?);
+
+    #[cfg(feature = "ledger")]
+    if let Some(index) = opt.ledger {
+        let ledger = Ledger::new(HDPath::LedgerLive(index), opt.chain_id).await?;
+        return run(opt, provider, ledger).await;
+    }
+    #[cfg(feature = "aws")]
+    if let Some(key_id) = opt.aws_kms_key_id.clone() {
+        let kms = rusoto_kms::KmsClient::new(rusoto_core::Region::default());
+        let aws_signer = AwsSigner::new(kms, key_id, opt.chain_id).await?;
+        return run(opt, provider, aws_signer).await;
+    }
+
+    let wallet = load_wallet(&opt)?;
+    run(opt, provider, wallet).await
+}
+
+async fn run<S>(opt: Opt, provider: Arc<Provider<Http>>, signer: S) -> Result<()>
+where
+    S: Signer + 'static,
+{
     let subscriptions = Subscriptions::new(opt.subscriptions, provider.clone());
     let token = IERC20::new(opt.token, provider.clone());
 
-    eprintln!("reading secret key from stdin...");
-    let mut secret_key = String::new();
-    std::io::stdin().read_to_string(&mut secret_key)?;
-    let wallet = Wallet::from_str(secret_key.trim())?.with_chain_id(opt.chain_id);
-    drop(secret_key);
-    let client = SignerMiddleware::new(provider, wallet.clone());
-    eprintln!("user: {}", wallet.address());
+    let client = SignerMiddleware::new(provider, signer);
+    eprintln!("user: {}", client.address());
 
-    let balance = token.balance_of(wallet.address()).await?;
+    let balance = token.balance_of(client.address()).await?;
     eprintln!("balance: {balance:?}");
 
     match opt.command {
         Commands::Active => {
             let active_sub: Subscription = subscriptions
-                .subscriptions(wallet.address())
+                .subscriptions(client.address())
                 .await?
                 .try_into()?;
             println!("{active_sub:?}");
@@ -102,83 +194,91 @@ async fn main() -> Result<()> {
                 .context("invalid sub duration")?;
             eprintln!("duration: {duration} s");
 
-            let call = token.approve(subscriptions.address(), U256::from(rate) * duration);
-            eprintln!("tx: {}", call.tx.data().unwrap());
-            let receipt = client.send_transaction(call.tx, None).await?.await?;
-            let status = receipt
-                .and_then(|receipt| Some(receipt.status?.as_u64()))
-                .unwrap_or(0);
-            eprintln!("approve status: {}", status);
-            ensure!(status == 1, "failed to approve token amount");
+            let amount = rate
+                .checked_mul_seconds(duration)
+                .context("subscription amount overflow")?;
+            eprintln!("amount: {amount}");
+
+            let call = token.approve(subscriptions.address(), amount.into());
+            send(&client, call.tx, "approve").await?;
 
             let start = start.timestamp() as u64;
-            let call = subscriptions.subscribe(start, start + duration, rate);
-            eprintln!("tx: {}", call.tx.data().unwrap());
-            let receipt = client.send_transaction(call.tx, None).await?.await?;
-            let status = receipt
-                .and_then(|receipt| Some(receipt.status?.as_u64()))
-                .unwrap_or(0);
-            eprintln!("subscribe status: {}", status);
-            ensure!(status == 1, "failed to subscribe");
+            let call = subscriptions.subscribe(start, start + duration, rate.0);
+            send(&client, call.tx, "subscribe").await?;
+        }
+
+        Commands::Extend { end } => {
+            let active_sub: Subscription = subscriptions
+                .subscriptions(client.address())
+                .await?
+                .try_into()?;
+            eprintln!("{active_sub:?}");
+            let now = Utc::now();
+            ensure!(active_sub.end > now, "no active subscription");
+            ensure!(
+                end > active_sub.end,
+                "end must be after the active subscription end"
+            );
+
+            // Resubscribing refunds the unlocked tokens of the active subscription, and locks the
+            // full amount for the new one from `max(block.timestamp, start)`. The approval covers
+            // an extra minute, in case the block timestamp is behind the local clock.
+            let extended = Subscription {
+                start: active_sub.start,
+                end,
+                rate: active_sub.rate,
+            };
+            let amount = extended
+                .unlocked_amount(now - chrono::Duration::minutes(1))
+                .context("subscription amount overflow")?;
+            eprintln!("amount: {amount}");
+
+            let call = token.approve(subscriptions.address(), amount.into());
+            send(&client, call.tx, "approve").await?;
+
+            let call = subscriptions.subscribe(
+                active_sub.start.timestamp() as u64,
+                end.timestamp() as u64,
+                active_sub.rate.0,
+            );
+            send(&client, call.tx, "extend").await?;
         }
 
         Commands::Unsubscribe => {
             let call = subscriptions.unsubscribe();
-            eprintln!("tx: {}", call.tx.data().unwrap());
-            let receipt = client.send_transaction(call.tx, None).await?.await?;
-            let status = receipt
-                .and_then(|receipt| Some(receipt.status?.as_u64()))
-                .unwrap_or(0);
-            eprintln!("unsubscribe status: {}", status);
-            ensure!(status == 1, "Failed to unsubscribe");
+            send(&client, call.tx, "unsubscribe").await?;
         }
 
         Commands::Collect => {
             let call = subscriptions.collect();
-            eprintln!("tx: {}", call.tx.data().unwrap());
-            let receipt = client.send_transaction(call.tx, None).await?.await?;
-            let status = receipt
-                .and_then(|receipt| Some(receipt.status?.as_u64()))
-                .unwrap_or(0);
-            eprintln!("collect status: {}", status);
-            ensure!(status == 1, "Failed to collect");
+            send(&client, call.tx, "collect").await?;
         }
 
-        Commands::AddAuthorizedSigner { signer } => {
-            let active_sub = subscriptions.subscriptions(wallet.address()).await?;
+        Commands::Signer(SignerCommands::Add { signer }) => {
+            let active_sub = subscriptions.subscriptions(client.address()).await?;
             eprintln!("{active_sub:?}");
             let call = subscriptions.add_authorized_signer(signer);
-            eprintln!("add authorized signer tx: {}", call.tx.data().unwrap());
-            let receipt = client.send_transaction(call.tx, None).await?.await?;
-            let status = receipt
-                .and_then(|receipt| Some(receipt.status?.as_u64()))
-                .unwrap_or(0);
-            eprintln!("add authorized signer receipt status: {}", status);
-            ensure!(status == 1, "Failed to add the authorized signer");
+            send(&client, call.tx, "add authorized signer").await?;
         }
 
-        Commands::RemoveAuthorizedSigner { signer } => {
-            let active_sub = subscriptions.subscriptions(wallet.address()).await?;
+        Commands::Signer(SignerCommands::Remove { signer }) => {
+            let active_sub = subscriptions.subscriptions(client.address()).await?;
             eprintln!("{active_sub:?}");
             let call = subscriptions.remove_authorized_signer(signer);
-            eprintln!("remove authorized signer tx: {}", call.tx.data().unwrap());
-            let receipt = client.send_transaction(call.tx, None).await?.await?;
-            let status = receipt
-                .and_then(|receipt| Some(receipt.status?.as_u64()))
-                .unwrap_or(0);
-            eprintln!("remove authorized signer receipt status: {}", status);
-            ensure!(status == 1, "Failed to remove the authorized signer");
+            send(&client, call.tx, "remove authorized signer").await?;
         }
 
-        Commands::Ticket {
+        Commands::Ticket(TicketCommands::Sign {
             signer,
             user,
             name,
             allowed_subgraphs,
             allowed_deployments,
             allowed_domains,
-        } => {
-            let signer = signer.unwrap_or(wallet.address());
+            expires_at,
+            nonce,
+        }) => {
+            let signer = signer.unwrap_or(client.address());
             let payload = TicketPayload {
                 chain_id: opt.chain_id,
                 contract: subscriptions.address(),
@@ -188,12 +288,16 @@ async fn main() -> Result<()> {
                 allowed_subgraphs,
                 allowed_deployments,
                 allowed_domains,
+                expires_at: expires_at.map(|t| t.timestamp() as u64),
+                nonce,
             };
 
             let user = payload.user.unwrap_or(payload.signer);
             ensure!(subscriptions.check_authorized_signer(user, signer).await?);
 
-            let ticket = payload.to_ticket_base64(&wallet)?;
+            let ticket = payload
+                .to_ticket_base64_with_signer(client.signer())
+                .await?;
 
             // check recovery
             TicketPayload::from_ticket_base64(&ticket)?;
@@ -201,11 +305,33 @@ async fn main() -> Result<()> {
             println!("{ticket}");
         }
 
-        Commands::Decode { ticket } => {
-            let (payload, _) = TicketPayload::from_ticket_base64(&ticket)?;
-            println!("\n{}", payload.verification_message());
-        }
+        Commands::Ticket(TicketCommands::Verify { ticket }) => verify_ticket(&ticket)?,
     }
 
     Ok(())
 }
+
+fn verify_ticket(ticket: &str) -> Result<()> {
+    let (payload, _) = if ticket.starts_with(TICKET_HEADER_PREFIX) {
+        TicketPayload::from_header(ticket)?
+    } else {
+        TicketPayload::from_ticket_base64(ticket)?
+    };
+    println!("\n{}", payload.verification_message());
+    Ok(())
+}
+
+/// Send the transaction and wait for its receipt, failing unless the transaction succeeded.
+async fn send<M>(client: &M, tx: TypedTransaction, name: &str) -> Result<()>
+where
+    M: Middleware + 'static,
+{
+    eprintln!("{name} tx: {}", tx.data().unwrap());
+    let receipt = client.send_transaction(tx, None).await?.await?;
+    let status = receipt
+        .and_then(|receipt| Some(receipt.status?.as_u64()))
+        .unwrap_or(0);
+    eprintln!("{name} status: {}", status);
+    ensure!(status == 1, "{name} transaction failed");
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use crate::Subscriptions;
+use anyhow::{anyhow, Context as _};
+use ethers::{abi::Address, providers::Middleware};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Source of the authorized signers set by subscription owners.
+pub trait AuthorizedSignerSource {
+    fn is_authorized_signer(
+        &self,
+        owner: Address,
+        signer: Address,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send;
+}
+
+impl<M: Middleware + 'static> AuthorizedSignerSource for Subscriptions<M> {
+    async fn is_authorized_signer(&self, owner: Address, signer: Address) -> anyhow::Result<bool> {
+        Ok(self.authorized_signers(owner, signer).call().await?)
+    }
+}
+
+/// Reads the `AuthorizedSigner` entities of the subscriptions subgraph.
+pub struct SubgraphAuthorizedSigners {
+    client: reqwest::Client,
+    url: reqwest::Url,
+}
+
+impl SubgraphAuthorizedSigners {
+    pub fn new(client: reqwest::Client, url: reqwest::Url) -> Self {
+        Self { client, url }
+    }
+}
+
+impl AuthorizedSignerSource for SubgraphAuthorizedSigners {
+    async fn is_authorized_signer(&self, owner: Address, signer: Address) -> anyhow::Result<bool> {
+        #[derive(Deserialize)]
+        struct Response {
+            data: Option<Data>,
+            errors: Option<Vec<serde_json::Value>>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Data {
+            authorized_signers: Vec<serde_json::Value>,
+        }
+
+        let query = r#"
+            query($user: String!, $signer: Bytes!) {
+                authorizedSigners(first: 1, where: { user: $user, signer: $signer }) { id }
+            }
+        "#;
+        let body = json!({
+            "query": query,
+            "variables": {
+                "user": format!("{owner:?}"),
+                "signer": format!("{signer:?}"),
+            },
+        });
+        let response: Response = self
+            .client
+            .post(self.url.clone())
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("subgraph request failed")?
+            .json()
+            .await
+            .context("invalid subgraph response")?;
+        if let Some(errors) = response.errors.filter(|errors| !errors.is_empty()) {
+            return Err(anyhow!("subgraph errors: {errors:?}"));
+        }
+        let data = response.data.context("missing subgraph response data")?;
+        Ok(!data.authorized_signers.is_empty())
+    }
+}
+
+/// Resolves whether a ticket signer may act on behalf of a subscription owner, caching results
+/// from the underlying source for `ttl`. The cache holds at most `capacity` entries, since its keys
+/// are taken from untrusted tickets.
+pub struct AuthorizedSigners<S> {
+    source: S,
+    ttl: Duration,
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<(Address, Address), (Instant, bool)>,
+    /// Keys in insertion order. A key may appear again after its entry is refreshed, earlier
+    /// appearances are skipped on eviction.
+    order: VecDeque<(Instant, (Address, Address))>,
+}
+
+impl<S: AuthorizedSignerSource> AuthorizedSigners<S> {
+    pub fn new(source: S, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            source,
+            ttl,
+            capacity,
+            cache: Default::default(),
+        }
+    }
+
+    /// Defined as `owner == signer || authorizedSigners[owner][signer]`.
+    pub async fn check(&self, owner: Address, signer: Address) -> anyhow::Result<bool> {
+        if owner == signer {
+            return Ok(true);
+        }
+        let key = (owner, signer);
+        if let Some((updated, authorized)) = self.cache.lock().unwrap().entries.get(&key) {
+            if updated.elapsed() < self.ttl {
+                return Ok(*authorized);
+            }
+        }
+        let authorized = self.source.is_authorized_signer(owner, signer).await?;
+        let mut cache = self.cache.lock().unwrap();
+        // Evict expired entries, then the oldest entries until there is room for this one.
+        while let Some(&(updated, oldest)) = cache.order.front() {
+            if (updated.elapsed() < self.ttl) && (cache.entries.len() < self.capacity) {
+                break;
+            }
+            cache.order.pop_front();
+            if cache.entries.get(&oldest).map(|(u, _)| *u) == Some(updated) {
+                cache.entries.remove(&oldest);
+            }
+        }
+        if self.capacity > 0 {
+            let now = Instant::now();
+            cache.order.push_back((now, key));
+            cache.entries.insert(key, (now, authorized));
+        }
+        Ok(authorized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Source {
+        authorized: (Address, Address),
+        calls: AtomicUsize,
+    }
+
+    impl AuthorizedSignerSource for Source {
+        async fn is_authorized_signer(
+            &self,
+            owner: Address,
+            signer: Address,
+        ) -> anyhow::Result<bool> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.authorized == (owner, signer))
+        }
+    }
+
+    #[tokio::test]
+    async fn check_authorized_signers() {
+        let owner = Address::from_low_u64_be(1);
+        let signer = Address::from_low_u64_be(2);
+        let other = Address::from_low_u64_be(3);
+        let source = Source {
+            authorized: (owner, signer),
+            calls: AtomicUsize::new(0),
+        };
+        let resolver = AuthorizedSigners::new(source, Duration::from_secs(60), 2);
+
+        assert!(resolver.check(owner, owner).await.unwrap());
+        assert_eq!(resolver.source.calls.load(Ordering::SeqCst), 0);
+
+        assert!(resolver.check(owner, signer).await.unwrap());
+        assert!(resolver.check(owner, signer).await.unwrap());
+        assert_eq!(resolver.source.calls.load(Ordering::SeqCst), 1);
+
+        assert!(!resolver.check(owner, other).await.unwrap());
+        assert!(!resolver.check(signer, owner).await.unwrap());
+        assert_eq!(resolver.source.calls.load(Ordering::SeqCst), 3);
+
+        // the oldest entry is evicted once the cache is full
+        {
+            let cache = resolver.cache.lock().unwrap();
+            assert_eq!(cache.entries.len(), 2);
+            assert!(!cache.entries.contains_key(&(owner, signer)));
+        }
+        assert!(resolver.check(owner, signer).await.unwrap());
+        assert_eq!(resolver.source.calls.load(Ordering::SeqCst), 4);
+
+        // expired entries are refreshed without growing the cache
+        let resolver = AuthorizedSigners::new(resolver.source, Duration::ZERO, 2);
+        for _ in 0..10 {
+            assert!(resolver.check(owner, signer).await.unwrap());
+        }
+        assert_eq!(resolver.source.calls.load(Ordering::SeqCst), 14);
+        assert_eq!(resolver.cache.lock().unwrap().order.len(), 1);
+    }
+}
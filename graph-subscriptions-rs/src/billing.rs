@@ -0,0 +1,142 @@
+use crate::{PaymentRatePerSecond, Subscription, TokenAmount};
+use anyhow::{ensure, Context as _};
+use chrono::{DateTime, Utc};
+
+/// Cost of replacing an active subscription with one at a different rate for the remainder of its
+/// term. This follows the contract semantics of `subscribe` overriding an active subscription: the
+/// unlocked tokens of the active subscription are refunded, and the replacement subscription is
+/// funded in full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Proration {
+    /// Unlocked tokens of the active subscription, refunded to the user.
+    pub refund: TokenAmount,
+    /// Tokens transferred from the user to fund the replacement subscription.
+    pub deposit: TokenAmount,
+    /// Tokens required from the user beyond the refund, `max(0, deposit - refund)`.
+    pub additional_deposit: TokenAmount,
+    /// Average rate over the full term of the original subscription, including the time already
+    /// paid for at the original rate.
+    pub effective_rate: PaymentRatePerSecond,
+}
+
+/// Calculate the cost of moving `sub` to the `target` rate at `now`, keeping its end timestamp.
+/// `now` is truncated to whole seconds, matching `block.timestamp`.
+pub fn prorate(
+    sub: &Subscription,
+    target: PaymentRatePerSecond,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Proration> {
+    let now = DateTime::from_timestamp(now.timestamp(), 0).context("invalid timestamp")?;
+    ensure!(sub.start < sub.end, "invalid subscription");
+    ensure!(sub.end > now, "subscription has expired");
+
+    let overflow = || "token amount overflow";
+    let locked = sub.locked_amount(now).with_context(overflow)?;
+    let refund = sub.unlocked_amount(now).with_context(overflow)?;
+    let remaining = sub.remaining_duration(now).num_seconds() as u64;
+    let deposit = target
+        .checked_mul_seconds(remaining)
+        .with_context(overflow)?;
+
+    let total = locked.checked_add(deposit).with_context(overflow)?;
+    let term = (sub.end - sub.start).num_seconds() as u128;
+    let effective_rate = PaymentRatePerSecond(total.0 / term);
+
+    Ok(Proration {
+        refund,
+        deposit,
+        additional_deposit: deposit.saturating_sub(refund),
+        effective_rate,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::at;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_prorate() {
+        let sub: Subscription = (100, 200, 2).try_into().unwrap();
+
+        // upgrade half way through the term
+        let proration = prorate(&sub, PaymentRatePerSecond(4), at(150)).unwrap();
+        assert_eq!(
+            proration,
+            Proration {
+                refund: TokenAmount(100),
+                deposit: TokenAmount(200),
+                additional_deposit: TokenAmount(100),
+                effective_rate: PaymentRatePerSecond(3),
+            }
+        );
+
+        // downgrade before the subscription starts
+        let proration = prorate(&sub, PaymentRatePerSecond(1), at(50)).unwrap();
+        assert_eq!(proration.refund, TokenAmount(200));
+        assert_eq!(proration.deposit, TokenAmount(100));
+        assert_eq!(proration.additional_deposit, TokenAmount::ZERO);
+        assert_eq!(proration.effective_rate, PaymentRatePerSecond(1));
+
+        assert!(prorate(&sub, PaymentRatePerSecond(4), at(200)).is_err());
+
+        // sub-second precision, as from `Utc::now()`
+        let now = DateTime::from_timestamp(150, 500_000_000).unwrap();
+        let proration = prorate(&sub, PaymentRatePerSecond(2), now).unwrap();
+        assert_eq!(proration.refund, TokenAmount(100));
+        assert_eq!(proration.deposit, TokenAmount(100));
+        assert_eq!(proration.effective_rate, PaymentRatePerSecond(2));
+    }
+
+    proptest! {
+        #[test]
+        fn prorate_invariants(
+            start in 0..1_000_000_i64,
+            len in 1..1_000_000_i64,
+            rate in 0..1_000_000_000_u128,
+            target in 0..1_000_000_000_u128,
+            now in 0..2_000_000_i64,
+            nanos in 0..1_000_000_000_u32,
+        ) {
+            let sub = Subscription {
+                start: at(start),
+                end: at(start + len),
+                rate: PaymentRatePerSecond(rate),
+            };
+            prop_assume!(sub.end > at(now));
+            let proration = prorate(
+                &sub,
+                PaymentRatePerSecond(target),
+                DateTime::from_timestamp(now, nanos).unwrap(),
+            )
+            .unwrap();
+
+            prop_assert_eq!(
+                proration.additional_deposit,
+                proration.deposit.checked_sub(proration.refund).unwrap_or(TokenAmount::ZERO)
+            );
+            prop_assert!(proration.effective_rate.0 >= rate.min(target));
+            prop_assert!(proration.effective_rate.0 <= rate.max(target));
+            if target >= rate {
+                prop_assert!(proration.deposit >= proration.refund);
+            }
+
+            // Moving back to the original rate reverses the token flows, since the contract
+            // replaces the subscription with one starting at `max(now, start)`.
+            let replacement = Subscription {
+                start: sub.start.max(at(now)),
+                end: sub.end,
+                rate: PaymentRatePerSecond(target),
+            };
+            let reverse = prorate(&replacement, sub.rate, at(now)).unwrap();
+            prop_assert_eq!(reverse.refund, proration.deposit);
+            prop_assert_eq!(reverse.deposit, proration.refund);
+
+            // Keeping the same rate is a no-op.
+            let same = prorate(&sub, sub.rate, at(now)).unwrap();
+            prop_assert_eq!(same.effective_rate, sub.rate);
+            prop_assert_eq!(same.deposit, same.refund);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{EpochDuration, Subscription, TokenAmount};
+use chrono::{DateTime, Utc};
+use std::ops::Range;
+
+impl EpochDuration {
+    /// Epoch number of the timestamp, rounded up to the next epoch boundary. Equivalent to the
+    /// contract's `timestampToEpoch`. Epoch `e` ends at timestamp `e * epochSeconds`. Returns
+    /// `None` for a zero epoch duration, which the contract cannot be deployed with.
+    pub fn epoch_at(&self, timestamp: DateTime<Utc>) -> Option<u64> {
+        let epoch = (timestamp.timestamp().max(0) as u64).checked_div(self.0)?;
+        epoch.checked_add(1)
+    }
+
+    /// Half-open timestamp range, in seconds, covered by the given epochs. Returns `None` on
+    /// overflow, or for a zero epoch duration.
+    fn seconds_range(&self, epochs: &Range<u64>) -> Option<Range<i64>> {
+        if self.0 == 0 {
+            return None;
+        }
+        let boundary = |epoch: u64| {
+            let seconds = epoch.saturating_sub(1).checked_mul(self.0)?;
+            i64::try_from(seconds).ok()
+        };
+        Some(boundary(epochs.start)?..boundary(epochs.end)?)
+    }
+}
+
+impl Subscription {
+    /// Tokens of this subscription collected by the contract owner when `collect` runs over the
+    /// given epochs, i.e. from `uncollectedEpoch` up to (but excluding) `currentEpoch() - offset`.
+    /// Defined as `rate * len([start, end) ∩ epochs)`. Returns `None` on overflow, or for a zero
+    /// epoch duration.
+    pub fn collectable(
+        &self,
+        epoch_seconds: EpochDuration,
+        epochs: Range<u64>,
+    ) -> Option<TokenAmount> {
+        let range = epoch_seconds.seconds_range(&epochs)?;
+        let len = self
+            .end
+            .timestamp()
+            .min(range.end)
+            .saturating_sub(self.start.timestamp().max(range.start));
+        self.rate.checked_mul_seconds(len.max(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::at, PaymentRatePerSecond};
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    /// Port of the contract's `_setEpochs` and `collect`, for a subscription created at or before
+    /// its start.
+    fn contract_collect(sub: &Subscription, epoch_seconds: u64, epochs: Range<u64>) -> i128 {
+        let (start, end, rate) = (
+            sub.start.timestamp() as i128,
+            sub.end.timestamp() as i128,
+            sub.rate.0 as i128,
+        );
+        let e = epoch_seconds as i128;
+        let epoch = |t: i128| (t / e) + 1;
+        let mut deltas: BTreeMap<i128, (i128, i128)> = BTreeMap::new();
+        let e1 = epoch(start);
+        deltas.entry(e1).or_default().0 += rate * e;
+        deltas.entry(e1).or_default().1 -= rate * (start - (e1 - 1) * e);
+        let e2 = epoch(end);
+        deltas.entry(e2).or_default().0 -= rate * e;
+        deltas.entry(e2).or_default().1 += rate * (end - (e2 - 1) * e);
+
+        let mut collect_per_epoch = 0;
+        let mut total = 0;
+        for n in 0..epochs.end as i128 {
+            let (delta, extra) = deltas.get(&n).copied().unwrap_or_default();
+            collect_per_epoch += delta;
+            if n >= epochs.start as i128 {
+                total += collect_per_epoch + extra;
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn collectable_example() {
+        // example from the contract's `_setEpochs`
+        let epoch_seconds = EpochDuration(6);
+        let sub: Subscription = (2, 9, 1).try_into().unwrap();
+        assert_eq!(epoch_seconds.epoch_at(sub.start), Some(1));
+        assert_eq!(epoch_seconds.epoch_at(sub.end), Some(2));
+        assert_eq!(sub.collectable(epoch_seconds, 1..2), Some(TokenAmount(4)));
+        assert_eq!(sub.collectable(epoch_seconds, 2..3), Some(TokenAmount(3)));
+        assert_eq!(sub.collectable(epoch_seconds, 0..10), Some(TokenAmount(7)));
+
+        assert_eq!(EpochDuration(0).epoch_at(sub.start), None);
+        assert_eq!(sub.collectable(EpochDuration(0), 0..10), None);
+        assert_eq!(sub.collectable(epoch_seconds, 0..u64::MAX), None);
+        assert_eq!(sub.collectable(EpochDuration(u64::MAX), 0..3), None);
+    }
+
+    fn subscription() -> impl Strategy<Value = Subscription> {
+        (0..1_000_000_u64, 1..1_000_000_u64, 0..1_000_000_000_u128).prop_map(
+            |(start, len, rate)| Subscription {
+                start: at(start as i64),
+                end: at((start + len) as i64),
+                rate: PaymentRatePerSecond(rate),
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn locked_and_unlocked_sum_to_total(
+            sub in subscription(),
+            t in 0..3_000_000_i64,
+            nanos in 0..1_000_000_000_u32,
+        ) {
+            let total = sub.rate.checked_mul_seconds((sub.end - sub.start).num_seconds() as u64);
+            let now = DateTime::from_timestamp(t, nanos).unwrap();
+            let locked = sub.locked_amount(now).unwrap();
+            let unlocked = sub.unlocked_amount(now).unwrap();
+            prop_assert_eq!(locked.checked_add(unlocked), total);
+            prop_assert!(sub.locked_amount(at(t + 1)).unwrap() >= locked);
+            prop_assert!(sub.unlocked_amount(at(t + 1)).unwrap() <= unlocked);
+        }
+
+        #[test]
+        fn collectable_matches_contract(
+            sub in subscription(),
+            epoch_seconds in 1..100_000_u64,
+            a in 0..100_u64,
+            b in 0..100_u64,
+        ) {
+            let epochs = a.min(b)..a.max(b);
+            let expected = contract_collect(&sub, epoch_seconds, epochs.clone());
+            let collectable = sub.collectable(EpochDuration(epoch_seconds), epochs).unwrap();
+            prop_assert_eq!(collectable.0 as i128, expected);
+        }
+
+        #[test]
+        fn collected_tokens_are_locked(
+            sub in subscription(),
+            epoch_seconds in 1..100_000_u64,
+            t in 0..3_000_000_i64,
+        ) {
+            let epoch_seconds = EpochDuration(epoch_seconds);
+            let current_epoch = epoch_seconds.epoch_at(at(t)).unwrap();
+            let collected = sub.collectable(epoch_seconds, 0..current_epoch).unwrap();
+            prop_assert!(collected <= sub.locked_amount(at(t)).unwrap());
+
+            let end_epoch = epoch_seconds.epoch_at(sub.end).unwrap();
+            let all = sub.collectable(epoch_seconds, 0..end_epoch + 1);
+            prop_assert_eq!(all, sub.locked_amount(sub.end));
+        }
+    }
+}
@@ -1,17 +1,35 @@
 use anyhow::{anyhow, ensure, Context};
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use ethers::{
-    abi::Address, contract::abigen, prelude::k256::ecdsa::SigningKey, signers::Wallet,
-    types::Signature, utils::hash_message,
+    abi::Address,
+    contract::abigen,
+    prelude::k256::ecdsa::SigningKey,
+    signers::{Signer, Wallet},
+    types::{Signature, U256},
+    utils::hash_message,
 };
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::{serde_as, skip_serializing_none, FromInto};
 use std::{
+    future::Future,
     io::{self, Write as _},
     str::FromStr as _,
 };
 
+mod authorized_signers;
+pub mod billing;
+mod epochs;
+pub mod permit;
+mod ticket_validator;
+mod units;
+
+pub use authorized_signers::{
+    AuthorizedSignerSource, AuthorizedSigners, SubgraphAuthorizedSigners,
+};
+pub use ticket_validator::{InMemoryNonceStore, NonceStore, TicketUsage, TicketValidator};
+pub use units::{EpochDuration, PaymentRatePerSecond, TokenAmount};
+
 abigen!(
     Subscriptions,
     "../contracts/build/Subscriptions.abi",
@@ -19,6 +37,12 @@ abigen!(
     IERC20,
     "../contracts/build/IERC20.abi",
     event_derives(serde::Deserialize, serde::Serialize);
+    IERC20Permit,
+    r#"[
+        function permit(address owner, address spender, uint256 value, uint256 deadline, uint8 v, bytes32 r, bytes32 s) external
+        function nonces(address owner) external view returns (uint256)
+        function DOMAIN_SEPARATOR() external view returns (bytes32)
+    ]"#;
 );
 
 // This is necessary intermediary to get the Address wrapper type over bytes to serialize &
@@ -56,6 +80,18 @@ impl<'de> Deserialize<'de> for AddressBytes {
     }
 }
 
+/// Prefix of tickets in the HTTP header format, identifying the format version.
+pub const TICKET_HEADER_PREFIX: &str = "v1.";
+/// Maximum length of a ticket in the HTTP header format.
+pub const TICKET_HEADER_MAX_LEN: usize = 4096;
+
+/// Half the order of the secp256k1 curve. Signatures with an `s` value above this are rejected in
+/// the header format, since `(r, n - s)` is an equally valid signature for the same message.
+const SECP256K1_HALF_N: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
 #[serde_as]
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -78,14 +114,17 @@ pub struct TicketPayload {
     // pub id: u64,
     // /// Maximum uses for tickets with matching identifiers. Defaults to 1 when omitted.
     // pub max_uses: Option<u64>,
-    // /// Unix timestamp after which the ticket is invalid.
-    // pub expiration: Option<u64>,
     /// Comma-separated list of subgraphs that can be queried with this ticket.
     pub allowed_subgraphs: Option<String>,
     /// Comma-separated list of subgraph deployments that can be queried with this ticket.
     pub allowed_deployments: Option<String>,
     /// Comma-separated list of origin domains that can send queries with this ticket.
     pub allowed_domains: Option<String>,
+    /// Unix timestamp after which the ticket is invalid.
+    pub expires_at: Option<u64>,
+    /// Single-use identifier. A ticket with a nonce is rejected after its first use by the same
+    /// signer.
+    pub nonce: Option<u64>,
 }
 
 impl TicketPayload {
@@ -99,10 +138,8 @@ impl TicketPayload {
             .context("invalid base64 (URL, nopad)")?;
 
         let signature_start = ticket.len().saturating_sub(65);
-        let signature = ticket[signature_start..]
-            .try_into()
-            .map(Signature::from)
-            .context("invalid signature")?;
+        let signature =
+            Signature::try_from(&ticket[signature_start..]).context("invalid signature")?;
 
         let payload: TicketPayload =
             serde_cbor_2::de::from_reader(&ticket[..signature_start]).context("invalid payload")?;
@@ -112,15 +149,85 @@ impl TicketPayload {
         Ok((payload, signature))
     }
 
+    /// Decode a ticket in the HTTP header format: `TICKET_HEADER_PREFIX` followed by the
+    /// base64url (nopad) encoded ticket. Unlike `from_ticket_base64`, this rejects tickets that
+    /// have alternative encodings of the same payload and signature.
+    pub fn from_header(header: &str) -> anyhow::Result<(Self, Signature)> {
+        ensure!(header.len() <= TICKET_HEADER_MAX_LEN, "ticket too large");
+        let ticket = header
+            .strip_prefix(TICKET_HEADER_PREFIX)
+            .context("unsupported ticket version")?;
+        let ticket = BASE64_URL_SAFE_NO_PAD
+            .decode(ticket)
+            .context("invalid base64 (URL, nopad)")?;
+
+        ensure!(ticket.len() > 65, "ticket too short");
+        let (payload_bytes, signature_bytes) = ticket.split_at(ticket.len() - 65);
+        let signature = Signature::try_from(signature_bytes).context("invalid signature")?;
+        ensure!(
+            matches!(signature.v, 27 | 28),
+            "invalid signature recovery id"
+        );
+        ensure!(
+            signature.s <= SECP256K1_HALF_N.into(),
+            "non-canonical signature"
+        );
+
+        let payload: TicketPayload =
+            serde_cbor_2::de::from_slice(payload_bytes).context("invalid payload")?;
+        ensure!(
+            serde_cbor_2::ser::to_vec(&payload)? == payload_bytes,
+            "non-canonical payload"
+        );
+        payload
+            .verify(&signature)
+            .context("failed to recover signer")?;
+        Ok((payload, signature))
+    }
+
+    pub fn to_header(&self, wallet: &Wallet<SigningKey>) -> anyhow::Result<String> {
+        let ticket = self.to_ticket_base64(wallet)?;
+        ticket_header(&ticket)
+    }
+
+    /// Like `to_header`, for any `TicketSigner`.
+    pub async fn to_header_with_signer<S: TicketSigner>(
+        &self,
+        signer: &S,
+    ) -> anyhow::Result<String> {
+        let ticket = self.to_ticket_base64_with_signer(signer).await?;
+        ticket_header(&ticket)
+    }
+
     pub fn to_ticket_base64(&self, wallet: &Wallet<SigningKey>) -> anyhow::Result<String> {
         let ticket = self.encode(wallet)?;
         Ok(BASE64_URL_SAFE_NO_PAD.encode(ticket))
     }
 
+    /// Like `to_ticket_base64`, for any `TicketSigner`.
+    pub async fn to_ticket_base64_with_signer<S: TicketSigner>(
+        &self,
+        signer: &S,
+    ) -> anyhow::Result<String> {
+        let ticket = self.encode_with_signer(signer).await?;
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(ticket))
+    }
+
     pub fn encode(&self, wallet: &Wallet<SigningKey>) -> anyhow::Result<Vec<u8>> {
-        let mut buf = serde_cbor_2::ser::to_vec(self)?;
-        buf.append(&mut self.sign_hash(wallet)?.to_vec());
-        Ok(buf)
+        ensure!(
+            wallet.address() == self.signer,
+            "signer does not match payload"
+        );
+        self.encode_signed(self.sign_hash(wallet)?)
+    }
+
+    /// Like `encode`, for any `TicketSigner`.
+    pub async fn encode_with_signer<S: TicketSigner>(&self, signer: &S) -> anyhow::Result<Vec<u8>> {
+        ensure!(
+            signer.signer_address() == self.signer,
+            "signer does not match payload"
+        );
+        self.encode_signed(signer.sign_ticket(self).await?)
     }
 
     pub fn sign_hash(&self, wallet: &Wallet<SigningKey>) -> anyhow::Result<Signature> {
@@ -128,6 +235,12 @@ impl TicketPayload {
         Ok(wallet.sign_hash(hash)?)
     }
 
+    fn encode_signed(&self, signature: Signature) -> anyhow::Result<Vec<u8>> {
+        let mut buf = serde_cbor_2::ser::to_vec(self)?;
+        buf.append(&mut signature.to_vec());
+        Ok(buf)
+    }
+
     pub fn verify(&self, signature: &Signature) -> anyhow::Result<Address> {
         let hash = hash_message(self.verification_message());
         let recovered_signer = signature.recover(hash)?;
@@ -151,9 +264,15 @@ impl TicketPayload {
         }
         writeln!(&mut cursor, "chain_id: {}", self.chain_id).unwrap();
         writeln!(&mut cursor, "contract: {:?}", self.contract).unwrap();
+        if let Some(expires_at) = self.expires_at {
+            writeln!(&mut cursor, "expires_at: {}", expires_at).unwrap();
+        }
         if let Some(name) = &self.name {
             writeln!(&mut cursor, "name: {}", name).unwrap();
         }
+        if let Some(nonce) = self.nonce {
+            writeln!(&mut cursor, "nonce: {}", nonce).unwrap();
+        }
         writeln!(&mut cursor, "signer: {:?}", self.signer).unwrap();
         if let Some(user) = self.user {
             writeln!(&mut cursor, "user: {:?}", user).unwrap();
@@ -162,11 +281,54 @@ impl TicketPayload {
     }
 }
 
+fn ticket_header(ticket: &str) -> anyhow::Result<String> {
+    let header = format!("{TICKET_HEADER_PREFIX}{ticket}");
+    ensure!(header.len() <= TICKET_HEADER_MAX_LEN, "ticket too large");
+    Ok(header)
+}
+
+/// Signs ticket payloads, producing the EIP-191 signature of `TicketPayload::verification_message`.
+/// This is implemented for all `ethers` signers, including local secret keys (`Wallet`), AWS KMS
+/// keys (`AwsSigner`, with the `aws` feature), and Ledger devices (`Ledger`, with the `ledger`
+/// feature).
+pub trait TicketSigner {
+    fn signer_address(&self) -> Address;
+    fn sign_ticket(
+        &self,
+        payload: &TicketPayload,
+    ) -> impl Future<Output = anyhow::Result<Signature>> + Send;
+}
+
+impl<S> TicketSigner for S
+where
+    S: Signer,
+    S::Error: 'static,
+{
+    fn signer_address(&self) -> Address {
+        self.address()
+    }
+
+    async fn sign_ticket(&self, payload: &TicketPayload) -> anyhow::Result<Signature> {
+        let mut signature = self.sign_message(payload.verification_message()).await?;
+        // Some signers, such as `AwsSigner`, apply EIP-155 to the recovery id of messages. Tickets
+        // use the low-s signature, with the 27/28 recovery id of EIP-191.
+        let mut recovery_id = signature.recovery_id()?.to_byte();
+        if signature.s > U256::from(SECP256K1_HALF_N) {
+            signature.s = (U256::from(SECP256K1_HALF_N) * 2 + 1) - signature.s;
+            recovery_id ^= 1;
+        }
+        signature.v = 27 + recovery_id as u64;
+        payload.verify(&signature)?;
+        Ok(signature)
+    }
+}
+
+/// A lockup of `rate` tokens per second for the half-open timestamp range `[start, end)`.
 #[derive(Debug)]
 pub struct Subscription {
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
-    pub rate: u128,
+    pub rate: PaymentRatePerSecond,
 }
 
 impl TryFrom<(u64, u64, u128)> for Subscription {
@@ -174,42 +336,235 @@ impl TryFrom<(u64, u64, u128)> for Subscription {
     fn try_from(from: (u64, u64, u128)) -> Result<Self, Self::Error> {
         let (start, end, rate) = from;
         let to_datetime = |t: u64| {
-            NaiveDateTime::from_timestamp_opt(t.try_into()?, 0)
-                .ok_or_else(|| anyhow!("invalid timestamp"))
-                .map(|t| DateTime::<Utc>::from_naive_utc_and_offset(t, Utc))
+            DateTime::from_timestamp(t.try_into()?, 0).ok_or_else(|| anyhow!("invalid timestamp"))
         };
         let start = to_datetime(start)?;
         let end = to_datetime(end)?;
-        Ok(Self { start, end, rate })
+        Ok(Self {
+            start,
+            end,
+            rate: PaymentRatePerSecond(rate),
+        })
+    }
+}
+
+impl Subscription {
+    pub fn is_active_at(&self, timestamp: DateTime<Utc>) -> bool {
+        (self.start <= timestamp) && (timestamp < self.end)
+    }
+
+    /// Defined as `max(0, end - max(timestamp, start))`.
+    pub fn remaining_duration(&self, timestamp: DateTime<Utc>) -> Duration {
+        Duration::seconds(self.remaining_seconds(timestamp))
+    }
+
+    /// Tokens collectable by the contract owner, which are not recoverable by the user. Defined as
+    /// `rate * max(0, min(timestamp, end) - start)`. Returns `None` on overflow.
+    pub fn locked_amount(&self, timestamp: DateTime<Utc>) -> Option<TokenAmount> {
+        let len = timestamp.timestamp().min(self.end.timestamp()) - self.start.timestamp();
+        self.rate.checked_mul_seconds(len.max(0) as u64)
+    }
+
+    /// Tokens recoverable by the user, which are not collectable by the contract owner. Defined as
+    /// `rate * max(0, end - max(timestamp, start))`. Returns `None` on overflow.
+    pub fn unlocked_amount(&self, timestamp: DateTime<Utc>) -> Option<TokenAmount> {
+        let len = self.remaining_seconds(timestamp);
+        self.rate.checked_mul_seconds(len as u64)
+    }
+
+    /// The contract only sees whole seconds (`block.timestamp`), so `timestamp` is truncated before
+    /// any arithmetic. Otherwise the locked and unlocked amounts would be rounded down separately,
+    /// and would no longer add up to the subscription total.
+    fn remaining_seconds(&self, timestamp: DateTime<Utc>) -> i64 {
+        let len = self.end.timestamp() - timestamp.timestamp().max(self.start.timestamp());
+        len.max(0)
     }
 }
 
 #[cfg(test)]
-#[test]
-fn test_ticket() {
-    use ethers::signers::Signer as _;
-
-    let wallet =
-        Wallet::from_str("0x4f3edf983ac636a65a842ce7c78d9aa706d3b113bce9c46f30d7d21715b23b1d")
-            .unwrap()
-            .with_chain_id(1337_u64);
-
-    let payload = TicketPayload {
-        chain_id: wallet.chain_id(),
-        contract: "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512"
-            .parse()
-            .unwrap(),
-        signer: wallet.address(),
-        user: None,
-        name: None,
-        allowed_subgraphs: None,
-        allowed_deployments: None,
-        allowed_domains: None,
-    };
-    println!("{payload:#?}");
-    let ticket = payload.to_ticket_base64(&wallet).unwrap();
-    println!("ticket: {ticket}");
-    let (extracted_payload, signature) = TicketPayload::from_ticket_base64(&ticket).unwrap();
-    println!("signature: {}", hex::encode(signature.to_vec()));
-    assert_eq!(payload, extracted_payload);
+mod tests {
+    use super::*;
+
+    /// Timestamp of `t` whole seconds since the Unix epoch.
+    pub(crate) fn at(t: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(t, 0).unwrap()
+    }
+
+    #[test]
+    fn test_ticket() {
+        let wallet =
+            Wallet::from_str("0x4f3edf983ac636a65a842ce7c78d9aa706d3b113bce9c46f30d7d21715b23b1d")
+                .unwrap()
+                .with_chain_id(1337_u64);
+
+        let payload = TicketPayload {
+            chain_id: wallet.chain_id(),
+            contract: "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512"
+                .parse()
+                .unwrap(),
+            signer: wallet.address(),
+            user: None,
+            name: None,
+            allowed_subgraphs: None,
+            allowed_deployments: None,
+            allowed_domains: None,
+            expires_at: None,
+            nonce: None,
+        };
+        println!("{payload:#?}");
+        let ticket = payload.to_ticket_base64(&wallet).unwrap();
+        println!("ticket: {ticket}");
+        let (extracted_payload, signature) = TicketPayload::from_ticket_base64(&ticket).unwrap();
+        println!("signature: {}", hex::encode(signature.to_vec()));
+        assert_eq!(payload, extracted_payload);
+    }
+
+    #[tokio::test]
+    async fn test_ticket_header() {
+        let wallet =
+            Wallet::from_str("0x4f3edf983ac636a65a842ce7c78d9aa706d3b113bce9c46f30d7d21715b23b1d")
+                .unwrap();
+        let payload = TicketPayload {
+            chain_id: 1337,
+            contract: "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512"
+                .parse()
+                .unwrap(),
+            signer: wallet.address(),
+            user: None,
+            name: Some("test".to_string()),
+            allowed_subgraphs: None,
+            allowed_deployments: None,
+            allowed_domains: None,
+            expires_at: None,
+            nonce: None,
+        };
+        let header = payload.to_header_with_signer(&wallet).await.unwrap();
+        assert!(header.starts_with(TICKET_HEADER_PREFIX));
+        assert_eq!(payload.to_header(&wallet).unwrap(), header);
+        let (extracted_payload, signature) = TicketPayload::from_header(&header).unwrap();
+        assert_eq!(payload, extracted_payload);
+
+        // The header format rejects the equivalent high-s signature, which the base64 format accepts.
+        let n = U256::from(SECP256K1_HALF_N) * 2 + 1;
+        let malleated = Signature {
+            r: signature.r,
+            s: n - signature.s,
+            v: if signature.v == 27 { 28 } else { 27 },
+        };
+        let mut ticket = serde_cbor_2::ser::to_vec(&payload).unwrap();
+        ticket.append(&mut malleated.to_vec());
+        let ticket = BASE64_URL_SAFE_NO_PAD.encode(ticket);
+        assert!(TicketPayload::from_ticket_base64(&ticket).is_ok());
+        assert!(TicketPayload::from_header(&format!("{TICKET_HEADER_PREFIX}{ticket}")).is_err());
+
+        assert!(TicketPayload::from_header(&header[TICKET_HEADER_PREFIX.len()..]).is_err());
+        let oversized = format!("{header}{}", "A".repeat(TICKET_HEADER_MAX_LEN));
+        assert!(TicketPayload::from_header(&oversized).is_err());
+
+        let other_signer = TicketPayload {
+            signer: Address::zero(),
+            ..payload
+        };
+        assert!(other_signer.to_header(&wallet).is_err());
+        assert!(other_signer.to_header_with_signer(&wallet).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eip155_ticket_signer() {
+        use async_trait::async_trait;
+        use ethers::types::transaction::{eip2718::TypedTransaction, eip712::Eip712};
+
+        /// Signs messages with an EIP-155 recovery id, like `AwsSigner`.
+        #[derive(Debug)]
+        struct Eip155Signer(Wallet<SigningKey>);
+
+        #[async_trait]
+        impl Signer for Eip155Signer {
+            type Error = ethers::signers::WalletError;
+            async fn sign_message<S: Send + Sync + AsRef<[u8]>>(
+                &self,
+                message: S,
+            ) -> Result<Signature, Self::Error> {
+                let mut signature = self.0.sign_message(message).await?;
+                signature.v = (signature.v - 27) + 35 + (2 * self.0.chain_id());
+                Ok(signature)
+            }
+            async fn sign_transaction(
+                &self,
+                tx: &TypedTransaction,
+            ) -> Result<Signature, Self::Error> {
+                self.0.sign_transaction(tx).await
+            }
+            async fn sign_typed_data<T: Eip712 + Send + Sync>(
+                &self,
+                payload: &T,
+            ) -> Result<Signature, Self::Error> {
+                self.0.sign_typed_data(payload).await
+            }
+            fn address(&self) -> Address {
+                self.0.address()
+            }
+            fn chain_id(&self) -> u64 {
+                self.0.chain_id()
+            }
+            fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+                Self(self.0.with_chain_id(chain_id))
+            }
+        }
+
+        let wallet =
+            Wallet::from_str("0x4f3edf983ac636a65a842ce7c78d9aa706d3b113bce9c46f30d7d21715b23b1d")
+                .unwrap();
+        for chain_id in [1337_u64, 42161, 421614] {
+            let signer = Eip155Signer(wallet.clone().with_chain_id(chain_id));
+            // vary the payload to cover both recovery ids
+            for i in 0..8 {
+                let payload = TicketPayload {
+                    chain_id,
+                    contract: Address::zero(),
+                    signer: wallet.address(),
+                    user: None,
+                    name: Some(format!("ticket {i}")),
+                    allowed_subgraphs: None,
+                    allowed_deployments: None,
+                    allowed_domains: None,
+                    expires_at: None,
+                    nonce: None,
+                };
+                let header = payload.to_header_with_signer(&signer).await.unwrap();
+                assert_eq!(TicketPayload::from_header(&header).unwrap().0, payload);
+                let ticket = payload.to_ticket_base64_with_signer(&signer).await.unwrap();
+                assert_eq!(
+                    TicketPayload::from_ticket_base64(&ticket).unwrap().0,
+                    payload
+                );
+                assert_eq!(payload.to_header(&wallet).unwrap(), header);
+            }
+        }
+    }
+
+    #[test]
+    fn test_subscription_amounts() {
+        let sub: Subscription = (100, 200, 2).try_into().unwrap();
+
+        assert!(!sub.is_active_at(at(99)));
+        assert!(sub.is_active_at(at(100)));
+        assert!(sub.is_active_at(at(199)));
+        assert!(!sub.is_active_at(at(200)));
+
+        for (t, locked, unlocked) in [(0, 0, 200), (100, 0, 200), (150, 100, 100), (300, 200, 0)] {
+            assert_eq!(sub.locked_amount(at(t)), Some(TokenAmount(locked)));
+            assert_eq!(sub.unlocked_amount(at(t)), Some(TokenAmount(unlocked)));
+            assert_eq!(
+                sub.remaining_duration(at(t)),
+                Duration::seconds(unlocked as i64 / 2)
+            );
+        }
+
+        // sub-second timestamps are truncated, like `block.timestamp`
+        let t = DateTime::from_timestamp(150, 500_000_000).unwrap();
+        assert_eq!(sub.locked_amount(t), Some(TokenAmount(100)));
+        assert_eq!(sub.unlocked_amount(t), Some(TokenAmount(100)));
+        assert_eq!(sub.remaining_duration(t), Duration::seconds(50));
+    }
 }
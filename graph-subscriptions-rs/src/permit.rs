@@ -0,0 +1,163 @@
+use crate::{IERC20Permit, TokenAmount};
+use anyhow::ensure;
+use ethers::{
+    abi::{self, Address, Token},
+    contract::ContractCall,
+    providers::Middleware,
+    signers::Signer,
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712},
+        Signature, U256,
+    },
+    utils::keccak256,
+};
+use std::convert::Infallible;
+
+/// ERC-2612 permit, allowing `spender` to transfer `value` tokens from `owner`. The permit must be
+/// submitted before `deadline`.
+///
+/// This allows a user to approve the subscriptions contract by signing a message, without holding
+/// gas tokens, when a relayer submits the permit call on their behalf. The user still has to call
+/// `subscribe` from their own account, since the contract subscribes `msg.sender`.
+#[derive(Clone, Debug)]
+pub struct Permit {
+    pub domain: EIP712Domain,
+    pub owner: Address,
+    pub spender: Address,
+    pub value: TokenAmount,
+    pub nonce: U256,
+    pub deadline: u64,
+}
+
+impl Eip712 for Permit {
+    type Error = Infallible;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        Ok(self.domain.clone())
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(
+            "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)",
+        ))
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(abi::encode(&[
+            Token::FixedBytes(Self::type_hash()?.to_vec()),
+            Token::Address(self.owner),
+            Token::Address(self.spender),
+            Token::Uint(self.value.into()),
+            Token::Uint(self.nonce),
+            Token::Uint(self.deadline.into()),
+        ])))
+    }
+}
+
+impl Permit {
+    /// Build a permit for `owner` using its current nonce on the token. The `name` and `version`
+    /// of the token's EIP-712 domain are checked against its `DOMAIN_SEPARATOR`.
+    pub async fn new<M: Middleware + 'static>(
+        token: &IERC20Permit<M>,
+        name: String,
+        version: String,
+        owner: Address,
+        spender: Address,
+        value: TokenAmount,
+        deadline: u64,
+    ) -> anyhow::Result<Self> {
+        let chain_id = token
+            .client()
+            .get_chainid()
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to get chain id: {err}"))?;
+        let domain = EIP712Domain {
+            name: Some(name),
+            version: Some(version),
+            chain_id: Some(chain_id),
+            verifying_contract: Some(token.address()),
+            salt: None,
+        };
+        ensure!(
+            domain.separator() == token.domain_separator().call().await?,
+            "permit domain does not match token DOMAIN_SEPARATOR"
+        );
+        let nonce = token.nonces(owner).call().await?;
+        Ok(Self {
+            domain,
+            owner,
+            spender,
+            value,
+            nonce,
+            deadline,
+        })
+    }
+
+    pub async fn sign<S>(&self, signer: &S) -> anyhow::Result<Signature>
+    where
+        S: Signer,
+        S::Error: 'static,
+    {
+        ensure!(
+            signer.address() == self.owner,
+            "signer is not the permit owner"
+        );
+        Ok(signer.sign_typed_data(self).await?)
+    }
+
+    /// Token call consuming the signed permit. This is meant to be sent by a relayer, since sending
+    /// it from the `owner` account costs more than an `approve` transaction.
+    pub fn call<M: Middleware>(
+        &self,
+        token: &IERC20Permit<M>,
+        signature: &Signature,
+    ) -> ContractCall<M, ()> {
+        let mut r = [0_u8; 32];
+        let mut s = [0_u8; 32];
+        signature.r.to_big_endian(&mut r);
+        signature.s.to_big_endian(&mut s);
+        token.permit(
+            self.owner,
+            self.spender,
+            self.value.into(),
+            self.deadline.into(),
+            signature.v as u8,
+            r,
+            s,
+        )
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_permit_signature() {
+    use ethers::signers::Wallet;
+    use std::str::FromStr as _;
+
+    let wallet =
+        Wallet::from_str("0x4f3edf983ac636a65a842ce7c78d9aa706d3b113bce9c46f30d7d21715b23b1d")
+            .unwrap();
+    let permit = Permit {
+        domain: EIP712Domain {
+            name: Some("USD Coin".to_string()),
+            version: Some("2".to_string()),
+            chain_id: Some(1337.into()),
+            verifying_contract: Some(Address::from_low_u64_be(1)),
+            salt: None,
+        },
+        owner: wallet.address(),
+        spender: Address::from_low_u64_be(2),
+        value: TokenAmount(100),
+        nonce: 0.into(),
+        deadline: 1_000,
+    };
+    let signature = permit.sign(&wallet).await.unwrap();
+    let digest = permit.encode_eip712().unwrap();
+    assert_eq!(signature.recover(digest).unwrap(), wallet.address());
+
+    let other = Permit {
+        owner: Address::from_low_u64_be(3),
+        ..permit
+    };
+    assert!(other.sign(&wallet).await.is_err());
+}
@@ -0,0 +1,244 @@
+use crate::TicketPayload;
+use anyhow::{bail, ensure};
+use chrono::{DateTime, Utc};
+use ethers::abi::Address;
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    sync::Mutex,
+};
+
+/// Record of ticket nonces that have already been used.
+pub trait NonceStore {
+    /// Record the use of `nonce` by `signer` at `timestamp`. Returns false if it has been used
+    /// before. Tickets are rejected once `expires_at` has passed, so the nonce may be forgotten
+    /// after that. Since calls may arrive out of order, or from gateway instances with skewed
+    /// clocks, a store that forgets a nonce must also reject it when a later call has an earlier
+    /// `timestamp`.
+    fn insert(
+        &self,
+        signer: Address,
+        nonce: u64,
+        expires_at: Option<u64>,
+        timestamp: u64,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send;
+}
+
+/// Nonce store for a single gateway instance. Nonces are dropped once their ticket has expired as
+/// of the latest `timestamp` seen, after which any use of the ticket is rejected. Nonces of tickets
+/// without an expiration are kept indefinitely.
+#[derive(Default)]
+pub struct InMemoryNonceStore {
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    /// Latest timestamp seen. Nonces of tickets expiring at or before it have been forgotten.
+    high_water: u64,
+    expires_at: HashMap<(Address, u64), Option<u64>>,
+    expirations: BTreeSet<(u64, Address, u64)>,
+}
+
+impl NonceStore for InMemoryNonceStore {
+    async fn insert(
+        &self,
+        signer: Address,
+        nonce: u64,
+        expires_at: Option<u64>,
+        timestamp: u64,
+    ) -> anyhow::Result<bool> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.high_water = seen.high_water.max(timestamp);
+        while let Some(&(expiration, signer, nonce)) = seen.expirations.first() {
+            if expiration > seen.high_water {
+                break;
+            }
+            seen.expirations.pop_first();
+            seen.expires_at.remove(&(signer, nonce));
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= seen.high_water) {
+            return Ok(false);
+        }
+
+        let previous = match seen.expires_at.get(&(signer, nonce)) {
+            Some(previous) => *previous,
+            None => {
+                seen.expires_at.insert((signer, nonce), expires_at);
+                if let Some(expires_at) = expires_at {
+                    seen.expirations.insert((expires_at, signer, nonce));
+                }
+                return Ok(true);
+            }
+        };
+        // Another ticket reusing the nonce keeps it from being forgotten while either is valid.
+        if let Some(previous) = previous {
+            if expires_at.is_none_or(|expires_at| expires_at > previous) {
+                seen.expirations.remove(&(previous, signer, nonce));
+                seen.expires_at.insert((signer, nonce), expires_at);
+                if let Some(expires_at) = expires_at {
+                    seen.expirations.insert((expires_at, signer, nonce));
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Attributes of a query, checked against the restrictions of the ticket sent with it.
+#[derive(Clone, Debug)]
+pub struct TicketUsage<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub deployment: Option<&'a str>,
+    pub subgraph: Option<&'a str>,
+    pub domain: Option<&'a str>,
+}
+
+/// Enforces the optional restrictions of a ticket payload. This does not verify the ticket
+/// signature, which is checked when decoding the ticket.
+pub struct TicketValidator<N> {
+    nonces: N,
+}
+
+impl<N: NonceStore> TicketValidator<N> {
+    pub fn new(nonces: N) -> Self {
+        Self { nonces }
+    }
+
+    pub async fn validate(
+        &self,
+        payload: &TicketPayload,
+        usage: &TicketUsage<'_>,
+    ) -> anyhow::Result<()> {
+        let timestamp = usage.timestamp.timestamp().max(0) as u64;
+        if let Some(expires_at) = payload.expires_at {
+            ensure!(timestamp < expires_at, "ticket expired");
+        }
+        check_allowed(
+            "deployment",
+            payload.allowed_deployments.as_deref(),
+            usage.deployment,
+        )?;
+        check_allowed(
+            "subgraph",
+            payload.allowed_subgraphs.as_deref(),
+            usage.subgraph,
+        )?;
+        check_allowed("domain", payload.allowed_domains.as_deref(), usage.domain)?;
+        // This must come last, so that the nonce is only consumed by a valid use of the ticket.
+        if let Some(nonce) = payload.nonce {
+            let unused = self
+                .nonces
+                .insert(payload.signer, nonce, payload.expires_at, timestamp)
+                .await?;
+            ensure!(unused, "ticket nonce already used");
+        }
+        Ok(())
+    }
+}
+
+fn check_allowed(field: &str, allowed: Option<&str>, value: Option<&str>) -> anyhow::Result<()> {
+    let allowed = match allowed {
+        Some(allowed) => allowed,
+        None => return Ok(()),
+    };
+    let value = match value {
+        Some(value) => value,
+        None => bail!("ticket requires a {field}"),
+    };
+    ensure!(
+        allowed.split(',').any(|entry| entry.trim() == value),
+        "{field} not allowed by ticket"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::at;
+
+    #[tokio::test]
+    async fn test_ticket_validator() {
+        let payload = TicketPayload {
+            chain_id: 1337,
+            contract: Address::zero(),
+            signer: Address::from_low_u64_be(1),
+            user: None,
+            name: None,
+            allowed_subgraphs: None,
+            allowed_deployments: Some("Qm1, Qm2".to_string()),
+            allowed_domains: None,
+            expires_at: Some(100),
+            nonce: Some(7),
+        };
+        let usage = TicketUsage {
+            timestamp: at(99),
+            deployment: Some("Qm2"),
+            subgraph: None,
+            domain: Some("example.com"),
+        };
+        let validator = TicketValidator::new(InMemoryNonceStore::default());
+
+        let expired = TicketUsage {
+            timestamp: at(100),
+            ..usage.clone()
+        };
+        assert!(validator.validate(&payload, &expired).await.is_err());
+        let other_deployment = TicketUsage {
+            deployment: Some("Qm3"),
+            ..usage.clone()
+        };
+        assert!(validator
+            .validate(&payload, &other_deployment)
+            .await
+            .is_err());
+
+        // rejected uses do not consume the nonce
+        assert!(validator.validate(&payload, &usage).await.is_ok());
+        assert!(validator.validate(&payload, &usage).await.is_err());
+
+        let unrestricted = TicketPayload {
+            allowed_deployments: None,
+            expires_at: None,
+            nonce: None,
+            ..payload
+        };
+        assert!(validator.validate(&unrestricted, &expired).await.is_ok());
+        assert!(validator.validate(&unrestricted, &expired).await.is_ok());
+
+        let far_future = TicketPayload {
+            expires_at: Some(u64::MAX),
+            ..unrestricted
+        };
+        assert!(validator.validate(&far_future, &expired).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_nonce_expiration() {
+        let store = InMemoryNonceStore::default();
+        let signer = Address::from_low_u64_be(1);
+        let other = Address::from_low_u64_be(2);
+        assert!(store.insert(signer, 1, Some(100), 0).await.unwrap());
+        assert!(store.insert(signer, 2, None, 0).await.unwrap());
+        assert!(store.insert(other, 1, Some(200), 0).await.unwrap());
+        assert!(!store.insert(signer, 1, Some(100), 99).await.unwrap());
+
+        // expired nonces are forgotten
+        assert!(store.insert(other, 3, Some(300), 100).await.unwrap());
+        assert_eq!(store.seen.lock().unwrap().expires_at.len(), 3);
+        assert!(store.insert(signer, 1, Some(300), 100).await.unwrap());
+        assert!(!store.insert(signer, 2, None, 100).await.unwrap());
+
+        // reusing a nonce with a later expiration keeps it longer
+        assert!(!store.insert(signer, 1, Some(400), 200).await.unwrap());
+        assert!(!store.insert(signer, 1, Some(300), 300).await.unwrap());
+        assert!(store.insert(signer, 1, Some(500), 400).await.unwrap());
+
+        // once forgotten, nonces are not accepted again from an earlier timestamp
+        assert!(store.insert(other, 4, Some(1_000), 900).await.unwrap());
+        assert!(store.insert(other, 5, None, 1_000).await.unwrap());
+        assert!(!store.insert(other, 4, Some(1_000), 950).await.unwrap());
+        assert!(!store.insert(other, 6, Some(1_000), 950).await.unwrap());
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::{ensure, Context as _};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Amount of tokens, denominated in the smallest unit of the payment token.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct TokenAmount(pub u128);
+
+/// Subscription payment rate, in token units per second. This matches the `rate` field of
+/// subscriptions in the contract.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct PaymentRatePerSecond(pub u128);
+
+/// Duration of a contract epoch, in seconds. This matches the `epochSeconds` value of the
+/// contract, which cannot be zero.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct EpochDuration(pub u64);
+
+impl TokenAmount {
+    pub const ZERO: Self = Self(0);
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl PaymentRatePerSecond {
+    /// Amount of tokens paid at this rate over the given number of seconds.
+    pub fn checked_mul_seconds(self, seconds: u64) -> Option<TokenAmount> {
+        self.0.checked_mul(seconds as u128).map(TokenAmount)
+    }
+}
+
+impl From<TokenAmount> for U256 {
+    fn from(value: TokenAmount) -> Self {
+        value.0.into()
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for PaymentRatePerSecond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for EpochDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for TokenAmount {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self).context("invalid token amount")
+    }
+}
+
+impl FromStr for PaymentRatePerSecond {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self).context("invalid payment rate")
+    }
+}
+
+impl FromStr for EpochDuration {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds: u64 = s.parse().context("invalid epoch duration")?;
+        seconds.try_into()
+    }
+}
+
+impl TryFrom<u64> for EpochDuration {
+    type Error = anyhow::Error;
+    fn try_from(seconds: u64) -> Result<Self, Self::Error> {
+        ensure!(seconds > 0, "epoch duration must be non-zero");
+        Ok(Self(seconds))
+    }
+}
+
+impl From<EpochDuration> for u64 {
+    fn from(value: EpochDuration) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rate_arithmetic() {
+    let rate = PaymentRatePerSecond(3);
+    assert_eq!(rate.checked_mul_seconds(10), Some(TokenAmount(30)));
+    assert_eq!(PaymentRatePerSecond(u128::MAX).checked_mul_seconds(2), None);
+    assert_eq!(TokenAmount(1).checked_sub(TokenAmount(2)), None);
+    assert_eq!(
+        TokenAmount(1).saturating_sub(TokenAmount(2)),
+        TokenAmount::ZERO
+    );
+    assert_eq!("60".parse::<EpochDuration>().unwrap(), EpochDuration(60));
+    assert!("0".parse::<EpochDuration>().is_err());
+    assert_eq!(
+        serde_json::from_str::<EpochDuration>("60").unwrap(),
+        EpochDuration(60)
+    );
+    assert!(serde_json::from_str::<EpochDuration>("0").is_err());
+    assert_eq!(serde_json::to_string(&EpochDuration(60)).unwrap(), "60");
+}